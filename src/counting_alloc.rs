@@ -0,0 +1,98 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Global allocator wrapper that counts the allocations made through it.
+///
+/// Every request is forwarded to the system allocator; the counters only
+/// ever grow, so the cost of an operation is the difference between two
+/// readings taken around it.
+///
+/// # Examples
+/// ```no_run
+/// use command_line_software::CountingAlloc;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: CountingAlloc = CountingAlloc::new();
+///
+/// let before = ALLOCATOR.allocations();
+/// let text = "search me".to_string();
+/// assert!(ALLOCATOR.allocations() > before);
+/// # drop(text);
+/// ```
+pub struct CountingAlloc {
+    allocations: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl CountingAlloc {
+    /// Creates an allocator with both counters at zero.
+    pub const fn new() -> CountingAlloc {
+        CountingAlloc {
+            allocations: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of allocations (including reallocations) made so far.
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// Total number of bytes requested by those allocations.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+impl Default for CountingAlloc {
+    fn default() -> CountingAlloc {
+        CountingAlloc::new()
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.record(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.record(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.record(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search_case_insensitive;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc::new();
+
+    #[test]
+    fn counters_grow_across_a_search() {
+        let allocations = ALLOCATOR.allocations();
+        let bytes = ALLOCATOR.bytes();
+
+        let results = search_case_insensitive("RUST", "Rust is fast\nrust is safe");
+
+        assert_eq!(2, results.len());
+        assert!(ALLOCATOR.allocations() > allocations);
+        assert!(ALLOCATOR.bytes() > bytes);
+    }
+}
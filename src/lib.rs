@@ -1,35 +1,251 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
-use std::fs;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
 
+#[cfg(feature = "profile-alloc")]
+mod counting_alloc;
+#[cfg(feature = "profile-alloc")]
+pub use counting_alloc::CountingAlloc;
+
+#[derive(Default)]
 pub struct Config {
     pub query: String,
     pub file_path: String,
     pub ignore_case: bool,
+    pub dedup_lines: bool,
+    pub stats: bool,
+    pub prefix: Option<String>,
+    pub word_unicode: bool,
+    pub line_terminator: Option<char>,
+    pub empty_marker: Option<String>,
+    pub replace_transform: Option<Transform>,
+    pub folds: Vec<(char, char)>,
+    pub positions: bool,
+    pub replace_diff: bool,
+    pub no_final_newline: bool,
+    pub byte_range: Option<Range<u64>>,
+    pub color_theme: Option<Theme>,
+    pub line_ending: LineEnding,
+    pub search_env: bool,
+    pub env_var: Option<String>,
+    pub histogram: bool,
+    pub strict_utf8: bool,
+    pub top: Option<usize>,
+    pub output_dir: Option<PathBuf>,
+    pub include_zero: bool,
+    pub rate: bool,
+    pub query_delimiter: Option<String>,
+    pub retry: u32,
+    pub pager: bool,
+    pub deterministic: bool,
+    pub count_non_matching: bool,
+    pub skip: usize,
+    pub diff_lines: Option<String>,
+    pub symmetric: bool,
+    pub max_highlights: Option<usize>,
+    pub bytes_only: bool,
+    #[cfg(feature = "profile-alloc")]
+    pub profile_alloc: bool,
+}
+
+/// Case transformation applied to every matched substring by
+/// `--replace-transform`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// `upper` - uppercase the matched text
+    Upper,
+    /// `lower` - lowercase the matched text
+    Lower,
+    /// `title` - uppercase the first letter of each word, lowercase the rest
+    Title,
+}
+
+impl Transform {
+    /// Applies the transformation to a matched substring.
+    ///
+    /// # Examples
+    /// ```
+    /// # use command_line_software::Transform;
+    /// assert_eq!(Transform::Upper.apply("rust"), "RUST");
+    /// assert_eq!(Transform::Title.apply("hELLO wORLD"), "Hello World");
+    /// ```
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Transform::Upper => text.to_uppercase(),
+            Transform::Lower => text.to_lowercase(),
+            Transform::Title => {
+                let mut titled = String::with_capacity(text.len());
+                let mut word_start = true;
+                for c in text.chars() {
+                    if word_start {
+                        titled.extend(c.to_uppercase());
+                    } else {
+                        titled.extend(c.to_lowercase());
+                    }
+                    word_start = !(c.is_alphanumeric() || c == '\'');
+                }
+                titled
+            }
+        }
+    }
+}
+/// An ANSI terminal style used to highlight matched text.
+///
+/// The style is stored as the parameter list of an SGR escape sequence, so
+/// `"1;31"` is rendered as `\x1b[1;31m` before the text and `\x1b[0m` after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Style {
+    pub sgr: String,
+}
+
+impl Style {
+    /// Creates a style from SGR parameters such as `"1;31"` (bold red).
+    pub fn new(sgr: &str) -> Style {
+        Style {
+            sgr: sgr.to_string(),
+        }
+    }
+
+    /// Wraps `text` in this style's escape sequence and a reset.
+    pub fn paint(&self, text: &str) -> String {
+        format!("\x1b[{}m{text}\x1b[0m", self.sgr)
+    }
+}
+
+impl Default for Style {
+    /// Bold red, the conventional grep match color.
+    fn default() -> Style {
+        Style::new("1;31")
+    }
+}
+
+/// Terminator written after every output line by `--line-ending`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    /// `lf` - `\n`, the default
+    #[default]
+    Lf,
+    /// `crlf` - `\r\n`
+    Crlf,
+}
+
+impl LineEnding {
+    /// Returns the terminator text.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+/// Named color presets as `(name, matches, filename, line number)` SGR
+/// parameters.
+const THEMES: &[(&str, &str, &str, &str)] = &[
+    ("default", "1;31", "35", "32"),
+    ("solarized", "1;38;5;166", "38;5;33", "38;5;64"),
+    ("mono", "1;4", "1", "2"),
+];
+
+/// A set of styles for the parts of the output that can be colored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub matches: Style,
+    pub filename: Style,
+    pub line_number: Style,
+}
+
+impl Theme {
+    /// Looks up one of the built-in presets: `default`, `solarized` or `mono`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use command_line_software::{Style, Theme};
+    /// let theme = Theme::named("solarized").unwrap();
+    /// assert_eq!(theme.matches, Style::new("1;38;5;166"));
+    /// assert!(Theme::named("neon").is_none());
+    /// ```
+    pub fn named(name: &str) -> Option<Theme> {
+        let (_, matches, filename, line_number) =
+            THEMES.iter().find(|(preset, ..)| *preset == name)?;
+        Some(Theme {
+            matches: Style::new(matches),
+            filename: Style::new(filename),
+            line_number: Style::new(line_number),
+        })
+    }
 }
+
 /// Configuration struct for the command-line search utility.
 ///
 /// Holds the search parameters and options for the text search operation.
-/// 
+///
 /// # Fields
 /// * `query` - The search term to look for in the file
 /// * `file_path` - The path to the file to be searched
-/// * `ignore_case` - Whether the search should be case-sensitive or case-insensitive
+/// * `ignore_case` - Inverted despite its name: `true` makes the search case-sensitive, as the `NO_IGNORE_CASE` variable it is set from says, and `false` (the default) ignores case
+/// * `dedup_lines` - Whether each unique matching line is printed only once per file
+/// * `stats` - Whether scan statistics are reported on stderr after the search
+/// * `prefix` - Text prepended to every printed line
+/// * `word_unicode` - Whether the query must match a whole word, using Unicode word boundaries
+/// * `line_terminator` - Character that ends a line, instead of `\n` / `\r\n`
+/// * `empty_marker` - Text printed when the search finds no matches
+/// * `replace_transform` - Case transformation applied to the matched text of each printed line
+/// * `folds` - Extra character equivalences for case-insensitive search, stored already lowercased as a table from each character to the one standing for its whole group (see `fold_table`)
+/// * `positions` - Whether the byte ranges of the matches are printed after each line
+/// * `replace_diff` - Whether `replace_transform` output is shown as `-`/`+` diff lines
+/// * `no_final_newline` - Whether the last line of output is left without a newline
+/// * `byte_range` - The part of the file to search, as byte offsets with the end exclusive
+/// * `color_theme` - Styles used to color the output, or `None` for plain text
+/// * `max_highlights` - How many matches per line `color_theme` highlights at most, or `None` for all of them
+/// * `line_ending` - Terminator written after each output line, whatever the input uses
+/// * `search_env` - Whether environment variables are searched instead of a file
+/// * `env_var` - The single environment variable searched, or `None` for all of them
+/// * `histogram` - Whether a chart of where the matches fall in the file is printed on stderr
+/// * `strict_utf8` - Whether invalid UTF-8 is reported with its byte offset and line
+/// * `top` - How many of the lines with the most matched bytes are printed, ranked, instead of every match
+/// * `output_dir` - Directory the results are written to, as `<filename>.matches`, instead of stdout
+/// * `include_zero` - Whether `output_dir` also gets a results file when nothing matched
+/// * `rate` - Whether the number of matches per KB of content is reported on stderr
+/// * `query_delimiter` - Separator splitting `query` into several queries, any of which may match
+/// * `retry` - How many more times a read failing with a transient error is attempted
+/// * `bytes_only` - Whether only the total size of the matching lines is printed
+/// * `skip` - How many matching lines are passed over before any is printed
+/// * `diff_lines` - Second file of `--diff-lines`, whose lines are subtracted from `file_path`'s, printing what is left instead of the matches
+/// * `symmetric` - Whether `diff_lines` also prints the lines only found in the other file
+/// * `pager` - Whether the results are shown through `$PAGER` (or `less`) instead of written to stdout
+/// * `count_non_matching` - Whether only the number of lines that do not match is printed
+/// * `deterministic` - Whether settings taken from environment variables are ignored
+/// * `profile_alloc` - Whether allocation counts for the search are reported on stderr
+///   (only with the `profile-alloc` feature)
 ///
 /// # Examples
 /// ```
-/// // Typical usage with case-sensitive search
+/// # use command_line_software::Config;
+/// // Typical usage: `ignore_case: false` ignores case
 /// let config = Config {
 ///     query: "rust".to_string(),
 ///     file_path: "example.txt".to_string(),
-///     ignore_case: false
+///     ignore_case: false,
+///     ..Default::default()
 /// };
-/// 
-/// // Case-insensitive search configuration
-/// let config_insensitive = Config {
+///
+/// // Case-sensitive search: `ignore_case` is inverted
+/// let config_sensitive = Config {
 ///     query: "Rust".to_string(),
 ///     file_path: "example.txt".to_string(),
-///     ignore_case: true
+///     ignore_case: true,
+///     ..Default::default()
 /// };
 /// ```
 impl Config {
@@ -44,44 +260,401 @@ impl Config {
     ///
     /// # Errors
     /// * Returns an error if no query string or file path is provided
+    /// * Returns an error if an option is unknown or is missing its value
+    /// * Returns an error if `--replace-diff` is given without `--replace-transform`
+    /// * Returns an error if `--symmetric` is given without `--diff-lines`
+    /// * Returns an error if `--diff-lines` is not given two files, or is
+    ///   combined with `--env` or an option shaping the match listing, such
+    ///   as `--stats`, `--top` or `--color-theme`
+    /// * Returns an error if `--query-delimiter` leaves no query, as with a
+    ///   query made only of delimiters
+    /// * Returns an error if `--color-theme` names an unknown preset
+    ///
+    /// # Options
+    /// * `--` - End the options: every argument after it is positional, so
+    ///   a query such as `--foo` can be searched for
+    /// * `--dedup-lines` - Print each unique matching line only once per file
+    /// * `--stats` - Report the number of lines scanned, empty and matched,
+    ///   and the bytes covered by the matched text
+    /// * `--profile-alloc` - Report the number of allocations and bytes
+    ///   allocated by the search; needs the `profile-alloc` feature
+    /// * `--count-non-matching` - Print only the number of lines that do not
+    ///   match
+    /// * `--bytes-only` - Print only the total bytes of the matching lines,
+    ///   each counted with one `--line-ending` terminator
+    /// * `--histogram` - Chart how many matches fall in each tenth of the file
+    /// * `--rate` - Report the number of matches per KB (1024 bytes) searched
+    /// * `--top <k>` - Only print the `k` lines with the most matched bytes,
+    ///   highest first, each preceded by its matched byte count
+    /// * `--skip <n>` - Do not print the first `n` matching lines; they are
+    ///   still counted by `--stats`
+    /// * `--query <query>` - Give the query as an option instead of as the
+    ///   first positional argument
+    /// * `--diff-lines` - Take two files, `a` and `b`, instead of a query and
+    ///   a file, and print the lines of `a` that are not in `b`, each once;
+    ///   with `--query`, only lines matching it are compared
+    /// * `--symmetric` - With `--diff-lines`, also print the lines of `b` that
+    ///   are not in `a`, after those of `a`
+    /// * `--deterministic` - Make the output depend only on the arguments and
+    ///   the input: `NO_IGNORE_CASE` and `$PAGER` are ignored. Searching is
+    ///   already single-threaded and in input order, and `--top` ties keep
+    ///   file order, so nothing else needs pinning
+    /// * `--pager` - Show the results in `$PAGER`, or `less -R` when it is
+    ///   unset, falling back to stdout if the pager cannot be started
+    /// * `--output-dir <dir>` - Write the results to `<dir>/<filename>.matches`
+    ///   instead of stdout, creating `dir` if needed
+    /// * `--include-zero` - With `--output-dir`, also write a results file when
+    ///   nothing matched
+    /// * `--prefix <text>` - Prepend `text` to every printed line
+    /// * `--query-delimiter <sep>` - Split the query on `sep` into several
+    ///   queries and match lines containing any of them
+    /// * `--word-unicode` - Only match the query as a whole word, where word
+    ///   characters are Unicode alphanumerics and `_`
+    /// * `--line-terminator <char>` - Split lines on `char` instead of `\n`;
+    ///   the escapes `\r`, `\n`, `\t` and `\0` are accepted
+    /// * `--empty-marker <text>` - Print `text` when nothing matched, or when
+    ///   `--skip` passed over every match
+    /// * `--replace-transform <op>` - Rewrite each match with `upper`, `lower`
+    ///   or `title` case
+    /// * `--replace-diff` - Preview `--replace-transform` as `- old` / `+ new`
+    ///   line pairs for every line it changes
+    /// * `--no-final-newline` - Do not terminate the last output line
+    /// * `--byte-range <start:end>` - Only read and search bytes `start` up to
+    ///   (not including) `end` of the file; a character cut by either end is
+    ///   dropped
+    /// * `--retry <n>` - Retry a file read that fails with a transient error
+    ///   (interrupted, would block, timed out) up to `n` times
+    /// * `--strict-utf8` - On invalid UTF-8, report the file offset and line of
+    ///   the first bad sequence; after `--byte-range`, the line is counted
+    ///   within the range
+    /// * `--color-theme <name>` - Highlight matches using the `default`,
+    ///   `solarized` or `mono` preset
+    /// * `--max-highlights <n>` - With `--color-theme`, highlight only the
+    ///   first `n` matches of a line and print the rest plain
+    /// * `--line-ending <lf|crlf>` - End output lines with `\n` (the default)
+    ///   or `\r\n`
+    /// * `--env` - Search environment variables as `NAME=value` lines instead
+    ///   of a file; the argument after the query then names a single variable
+    ///   to search and may be left out. Newlines and carriage returns in
+    ///   values are written as `\n` and `\r`, keeping one line per variable
+    /// * `--fold <a=b>` - Treat `a` as equal to `b` in case-insensitive search;
+    ///   may be repeated, and rules sharing a character join into one group,
+    ///   so `a=b` with `b=c` makes all three equal
+    /// * `--positions` - Follow each matching line with the byte ranges of its
+    ///   matches, like `  @ 4-7, 12-15`
     ///
     /// # Environment Variables
-    /// * `NO_IGNORE_CASE` - If set, enables case-insensitive search
+    /// * `NO_IGNORE_CASE` - If set, enables case-insensitive search (ignored
+    ///   with `--deterministic`)
     ///
     /// # Examples
     /// ```
+    /// # use command_line_software::Config;
     /// // Typical usage with valid arguments
     /// let args = vec!["program_name".to_string(), "query".to_string(), "file.txt".to_string()];
     /// let config = Config::build(args.into_iter()).unwrap();
-    /// 
+    ///
     /// // Example showing error handling with insufficient arguments
     /// let incomplete_args = vec!["program_name".to_string()];
     /// let result = Config::build(incomplete_args.into_iter());
     /// assert!(result.is_err());
     /// ```
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
+    pub fn build(args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
+        Config::build_with_env(args, |name| env::var(name).is_ok())
+    }
+
+    /// Does the work of `build`, asking `is_set` whether an environment
+    /// variable is set so the environment can be replaced in tests.
+    fn build_with_env(
+        mut args: impl Iterator<Item = String>,
+        is_set: impl Fn(&str) -> bool,
+    ) -> Result<Config, &'static str> {
         //ignore the first item on the iterator because is the name of the program
         args.next();
 
-        let query: String = match args.next() {
-            Some(args) => args,
-            None => return Err("didn't get query string"),
-        };
-        let file_path: String = match args.next() {
-            Some(args) => args,
-            None => return Err("no file path passed"),
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+        let mut query = None;
+        let mut diff_lines = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dedup-lines" => config.dedup_lines = true,
+                "--stats" => config.stats = true,
+                "--histogram" => config.histogram = true,
+                "--rate" => config.rate = true,
+                "--count-non-matching" => config.count_non_matching = true,
+                "--bytes-only" => config.bytes_only = true,
+                #[cfg(feature = "profile-alloc")]
+                "--profile-alloc" => config.profile_alloc = true,
+                "--strict-utf8" => config.strict_utf8 = true,
+                "--include-zero" => config.include_zero = true,
+                "--pager" => config.pager = true,
+                "--deterministic" => config.deterministic = true,
+                "--retry" => {
+                    let value = args.next().ok_or("--retry needs a value")?;
+                    config.retry = value.parse().map_err(|_| "--retry must be a number")?;
+                }
+                "--output-dir" => {
+                    let value = args.next().ok_or("--output-dir needs a value")?;
+                    config.output_dir = Some(PathBuf::from(value));
+                }
+                "--skip" => {
+                    let value = args.next().ok_or("--skip needs a value")?;
+                    config.skip = value.parse().map_err(|_| "--skip must be a number")?;
+                }
+                "--diff-lines" => diff_lines = true,
+                "--query" => query = Some(args.next().ok_or("--query needs a value")?),
+                "--symmetric" => config.symmetric = true,
+                "--top" => {
+                    let value = args.next().ok_or("--top needs a value")?;
+                    config.top = Some(value.parse().map_err(|_| "--top must be a number")?);
+                }
+                "--prefix" => config.prefix = Some(args.next().ok_or("--prefix needs a value")?),
+                "--word-unicode" => config.word_unicode = true,
+                "--line-terminator" => {
+                    let value = args.next().ok_or("--line-terminator needs a value")?;
+                    config.line_terminator = Some(parse_terminator(&value)?);
+                }
+                "--query-delimiter" => {
+                    let value = args.next().ok_or("--query-delimiter needs a value")?;
+                    if value.is_empty() {
+                        return Err("--query-delimiter must not be empty");
+                    }
+                    config.query_delimiter = Some(value);
+                }
+                "--empty-marker" => {
+                    config.empty_marker = Some(args.next().ok_or("--empty-marker needs a value")?)
+                }
+                "--replace-transform" => {
+                    let value = args.next().ok_or("--replace-transform needs a value")?;
+                    config.replace_transform = Some(match value.as_str() {
+                        "upper" => Transform::Upper,
+                        "lower" => Transform::Lower,
+                        "title" => Transform::Title,
+                        _ => return Err("--replace-transform must be upper, lower or title"),
+                    });
+                }
+                "--positions" => config.positions = true,
+                "--env" => config.search_env = true,
+                "--replace-diff" => config.replace_diff = true,
+                "--no-final-newline" => config.no_final_newline = true,
+                "--color-theme" => {
+                    let value = args.next().ok_or("--color-theme needs a value")?;
+                    config.color_theme =
+                        Some(Theme::named(&value).ok_or("unknown --color-theme preset")?);
+                }
+                "--max-highlights" => {
+                    let value = args.next().ok_or("--max-highlights needs a value")?;
+                    let max = value
+                        .parse()
+                        .map_err(|_| "--max-highlights must be a number")?;
+                    config.max_highlights = Some(max);
+                }
+                "--line-ending" => {
+                    let value = args.next().ok_or("--line-ending needs a value")?;
+                    config.line_ending = match value.as_str() {
+                        "lf" => LineEnding::Lf,
+                        "crlf" => LineEnding::Crlf,
+                        _ => return Err("--line-ending must be lf or crlf"),
+                    };
+                }
+                "--byte-range" => {
+                    let value = args.next().ok_or("--byte-range needs a value")?;
+                    config.byte_range = Some(parse_byte_range(&value)?);
+                }
+                "--fold" => {
+                    let value = args.next().ok_or("--fold needs a value")?;
+                    config.folds.push(parse_fold(&value)?);
+                }
+                "--" => {
+                    positional.extend(args.by_ref());
+                    break;
+                }
+                _ if arg.starts_with("--") => return Err("unknown option"),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+
+        config.query = match query {
+            Some(query) => query,
+            // An empty query matches every line, so all of them are compared.
+            None if diff_lines => String::new(),
+            None => match positional.next() {
+                Some(args) => args,
+                None => return Err("didn't get query string"),
+            },
         };
+        if diff_lines {
+            if config.search_env {
+                return Err("--diff-lines cannot search the environment");
+            }
+            match (positional.next(), positional.next()) {
+                (Some(a), Some(b)) => {
+                    config.file_path = a;
+                    config.diff_lines = Some(b);
+                }
+                _ => return Err("--diff-lines needs two files"),
+            }
+            // The difference is plain lines, so there is nothing to count,
+            // rank or highlight.
+            if config.stats
+                || config.rate
+                || config.histogram
+                || config.color_theme.is_some()
+                || config.max_highlights.is_some()
+                || config.positions
+                || config.top.is_some()
+                || config.skip > 0
+                || config.count_non_matching
+                || config.bytes_only
+                || config.replace_transform.is_some()
+            {
+                return Err("--diff-lines cannot be combined with match listing options");
+            }
+        } else if config.search_env {
+            config.env_var = positional.next();
+        } else {
+            config.file_path = match positional.next() {
+                Some(args) => args,
+                None => return Err("no file path passed"),
+            };
+        }
+
+        if config.replace_diff && config.replace_transform.is_none() {
+            return Err("--replace-diff needs --replace-transform");
+        }
+        if config.symmetric && config.diff_lines.is_none() {
+            return Err("--symmetric needs --diff-lines");
+        }
+        if config.queries().is_empty() {
+            return Err("no query to match");
+        }
 
-        let ignore_case = env::var("NO_IGNORE_CASE").is_ok();
+        config.folds = fold_table(&config.folds);
+        config.ignore_case = !config.deterministic && is_set("NO_IGNORE_CASE");
         // This make the enviroment variable persistend along the command line session.
         // to remove the signed enviroment variable use Remove-Item Env:NO_IGNORE_CASE
 
-        Ok(Config {
-            query,
-            file_path,
-            ignore_case,
-        })
+        Ok(config)
+    }
+
+    /// Returns the queries a line is matched against.
+    ///
+    /// This is just `query`, unless a `query_delimiter` is set: then the query
+    /// is split on it, each piece is trimmed, and empty pieces are dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// # use command_line_software::Config;
+    /// let config = Config {
+    ///     query: "error, warning,,fatal".to_string(),
+    ///     query_delimiter: Some(",".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(config.queries(), vec!["error", "warning", "fatal"]);
+    /// ```
+    pub fn queries(&self) -> Vec<&str> {
+        match &self.query_delimiter {
+            Some(delimiter) => self
+                .query
+                .split(delimiter.as_str())
+                .map(str::trim)
+                .filter(|query| !query.is_empty())
+                .collect(),
+            None => vec![self.query.as_str()],
+        }
+    }
+}
+
+/// Parses the value of `--line-terminator` into a single character.
+fn parse_terminator(value: &str) -> Result<char, &'static str> {
+    match value {
+        "\\r" => return Ok('\r'),
+        "\\n" => return Ok('\n'),
+        "\\t" => return Ok('\t'),
+        "\\0" => return Ok('\0'),
+        _ => {}
+    }
+
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err("--line-terminator must be a single character"),
+    }
+}
+
+/// Parses a `--byte-range` value such as `100:200`.
+fn parse_byte_range(value: &str) -> Result<Range<u64>, &'static str> {
+    let (start, end) = value
+        .split_once(':')
+        .ok_or("--byte-range must look like start:end")?;
+    let start: u64 = start
+        .parse()
+        .map_err(|_| "--byte-range start is not a number")?;
+    let end: u64 = end
+        .parse()
+        .map_err(|_| "--byte-range end is not a number")?;
+    if start > end {
+        return Err("--byte-range start is after its end");
+    }
+    Ok(start..end)
+}
+
+/// Parses a `--fold` rule such as `0=O` into a pair of lowercased characters.
+fn parse_fold(value: &str) -> Result<(char, char), &'static str> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some(from), Some('='), Some(to), None) => Ok((fold_char(from), fold_char(to))),
+        _ => Err("--fold must look like a=b"),
+    }
+}
+
+/// Lowercases a single character, keeping it unchanged when its lowercase
+/// form is more than one character.
+fn fold_char(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => c,
+    }
+}
+
+/// Turns `--fold` rules into a table mapping every character of a group of
+/// equal characters to the group's smallest one.
+///
+/// Rules are equivalences, so `a=b` and `b=c` put `a`, `b` and `c` in one
+/// group, and `a=b` with `b=a` is the same as `a=b` alone. Mapping through
+/// the table once is then enough for `translate`. A group's smallest
+/// character maps to itself and is left out. A table passed back in comes out
+/// unchanged.
+fn fold_table(rules: &[(char, char)]) -> Vec<(char, char)> {
+    fn root(parent: &HashMap<char, char>, mut c: char) -> char {
+        while let Some(&up) = parent.get(&c) {
+            if up == c {
+                break;
+            }
+            c = up;
+        }
+        c
     }
+
+    // Union-find, where each root is the smallest character of its group.
+    let mut parent: HashMap<char, char> = HashMap::new();
+    for &(a, b) in rules {
+        let (a, b) = (root(&parent, a), root(&parent, b));
+        let (low, high) = if a < b { (a, b) } else { (b, a) };
+        parent.insert(low, low);
+        parent.insert(high, low);
+    }
+
+    let mut table: Vec<(char, char)> = parent
+        .keys()
+        .map(|&c| (c, root(&parent, c)))
+        .filter(|(c, to)| c != to)
+        .collect();
+    table.sort_unstable();
+    table
 }
 
 /// Runs the text search operation based on the provided configuration.
@@ -95,40 +668,837 @@ impl Config {
 ///
 /// # Errors
 /// * Fails if the specified file cannot be read
+/// * Fails if the text read is not valid UTF-8; with `strict_utf8` the error
+///   says where
 ///
 /// # Examples
 /// ```no_run
+/// # use command_line_software::{run, Config};
 /// // Typical usage with a valid configuration
 /// let config = Config {
 ///     query: "rust".to_string(),
 ///     file_path: "example.txt".to_string(),
-///     ignore_case: false
+///     ignore_case: false,
+///     ..Default::default()
 /// };
 /// run(config).expect("Search operation failed");
-/// 
+///
 /// // Example with a non-existent file (will return an error)
 /// let config_error = Config {
 ///     query: "rust".to_string(),
 ///     file_path: "non_existent.txt".to_string(),
-///     ignore_case: false
+///     ignore_case: false,
+///     ..Default::default()
 /// };
 /// assert!(run(config_error).is_err());
 /// ```
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.file_path)?;
-
-    let results = if config.ignore_case {
-        search(&config.query, &contents)
+    let contents = if config.search_env {
+        env_contents(env::vars_os(), config.env_var.as_deref())
+    } else {
+        read_file(&config, &config.file_path)?
+    };
+    let other = match &config.diff_lines {
+        Some(other) => Some(read_file(&config, other)?),
+        None => None,
+    };
+    let print = |mut out: &mut dyn Write| match &other {
+        Some(other) => print_diff_lines(&config, &contents, other, &mut out),
+        None => print_matches(&config, &contents, &mut out),
+    };
+    let source = if config.search_env {
+        "environment"
     } else {
-        search_case_insensitive(&config.query, &contents)
+        &config.file_path
+    };
+
+    let stats = match &config.output_dir {
+        Some(dir) => {
+            let mut results = Vec::new();
+            let stats = print(&mut results)?;
+            if stats.matches > 0 || config.include_zero {
+                fs::create_dir_all(dir)?;
+                fs::write(output_file_path(dir, source), results)?;
+            }
+            stats
+        }
+        None if config.pager => print_paged(&config, print)?,
+        None => print(&mut io::stdout().lock())?,
     };
 
-    for line in results {
-        println!("{line}");
+    if config.stats {
+        eprintln!(
+            "{source}: {} matches, {} lines scanned ({} empty), {} bytes matched",
+            stats.matches, stats.lines, stats.empty_lines, stats.matched_bytes
+        );
+    }
+
+    if config.rate {
+        let rate = match_rate(stats.matches, contents.len());
+        eprintln!("{source}: {rate:.2} matches/KB");
+    }
+
+    if config.histogram {
+        let counts = histogram(&stats.match_lines, stats.lines, HISTOGRAM_BUCKETS);
+        eprint!("{}", render_histogram(&counts, stats.lines));
     }
 
     Ok(())
 }
+
+/// Pager started by `--pager` when `$PAGER` is not set or `--deterministic`
+/// is given.
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Writes the results of `print` into the user's pager, or to stdout when no
+/// pager can be started.
+///
+/// Quitting the pager before the end closes the pipe; the rest of the output
+/// is then discarded rather than treated as an error, and the scan still
+/// runs to the end so the statistics stay complete.
+fn print_paged(
+    config: &Config,
+    print: impl Fn(&mut dyn Write) -> io::Result<ScanStats>,
+) -> io::Result<ScanStats> {
+    let command = match env::var("PAGER") {
+        Ok(command) if !config.deterministic => command,
+        _ => DEFAULT_PAGER.to_string(),
+    };
+    let mut pager = match spawn_pager(&command, Stdio::inherit()) {
+        Ok(pager) => pager,
+        Err(_) => return print(&mut io::stdout().lock()),
+    };
+
+    // The pager's stdin is dropped at the end of the match, and the pager is
+    // waited on even when writing failed, so it never outlives the process
+    // and keeps the terminal.
+    let result = match pager.stdin.take() {
+        Some(stdin) => {
+            let mut out = IgnoreBrokenPipe {
+                inner: stdin,
+                closed: false,
+            };
+            print(&mut out)
+        }
+        None => print(&mut io::stdout().lock()),
+    };
+    pager.wait()?;
+    result
+}
+
+/// Starts `command` (a program followed by whitespace-separated arguments)
+/// with a piped stdin for the results and `stdout` as its output.
+fn spawn_pager(command: &str, stdout: Stdio) -> io::Result<Child> {
+    let mut parts = command.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty pager command"))?;
+    Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .spawn()
+}
+
+/// Writer that silently discards everything once the reader on the other
+/// end of the pipe has gone away.
+struct IgnoreBrokenPipe<W> {
+    inner: W,
+    closed: bool,
+}
+
+impl<W: Write> Write for IgnoreBrokenPipe<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Ok(buf.len());
+        }
+        match self.inner.write(buf) {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(buf.len())
+            }
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.flush() {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Returns the `--output-dir` results file for the input `source`, named
+/// after its file name with `.matches` appended.
+fn output_file_path(dir: &Path, source: &str) -> PathBuf {
+    let mut name = Path::new(source)
+        .file_name()
+        .unwrap_or(source.as_ref())
+        .to_os_string();
+    name.push(".matches");
+    dir.join(name)
+}
+
+/// Computes the `--rate` density of `matches` in `bytes` of content, in
+/// matches per KB. Empty content has a rate of zero.
+fn match_rate(matches: usize, bytes: usize) -> f64 {
+    if bytes == 0 {
+        return 0.0;
+    }
+    matches as f64 * 1024.0 / bytes as f64
+}
+
+/// Number of line ranges the file is divided into by `--histogram`.
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Widest bar drawn by `--histogram`, in characters.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Counts how many of the 1-based `match_lines` fall in each of `buckets`
+/// equal line ranges of a file with `total_lines` lines.
+///
+/// Files shorter than `buckets` lines get one bucket per line.
+fn histogram(match_lines: &[usize], total_lines: usize, buckets: usize) -> Vec<usize> {
+    let buckets = buckets.min(total_lines);
+    let mut counts = vec![0; buckets];
+    for &line in match_lines {
+        counts[(line - 1) * buckets / total_lines] += 1;
+    }
+    counts
+}
+
+/// Draws `counts` from `histogram` as one `#` bar per line range.
+fn render_histogram(counts: &[usize], total_lines: usize) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    let mut chart = String::new();
+    for (bucket, &count) in counts.iter().enumerate() {
+        let first = bucket * total_lines / counts.len() + 1;
+        let last = (bucket + 1) * total_lines / counts.len();
+        let width = match count {
+            0 => 0,
+            _ => (count * HISTOGRAM_WIDTH / max).max(1),
+        };
+        chart.push_str(&format!(
+            "{:>15} | {} {count}\n",
+            format!("{first}-{last}"),
+            "#".repeat(width)
+        ));
+    }
+    chart
+}
+
+/// Renders the environment `vars` (`env::vars_os()` outside of tests) as
+/// `NAME=value` lines, one per variable, so it can be searched like file
+/// contents.
+///
+/// With `name`, only that variable is included (nothing if it is unset).
+/// Names or values that are not valid Unicode are converted lossily. A value
+/// spanning several lines (a shell function, a PEM certificate) has its `\n`
+/// and `\r` escaped, so a match always prints the whole `NAME=value` record.
+fn env_contents(vars: impl Iterator<Item = (OsString, OsString)>, name: Option<&str>) -> String {
+    let mut contents = String::new();
+    for (key, value) in vars {
+        let key = key.to_string_lossy();
+        if name.is_some_and(|name| name != key) {
+            continue;
+        }
+        contents.push_str(&key);
+        contents.push('=');
+        for c in value.to_string_lossy().chars() {
+            match c {
+                '\n' => contents.push_str("\\n"),
+                '\r' => contents.push_str("\\r"),
+                c => contents.push(c),
+            }
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+/// Base delay before retrying a failed read; attempt `n` waits `n` times it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Reads the file at `path` as text, honoring `byte_range`, `retry` and
+/// `strict_utf8`.
+fn read_file(config: &Config, path: &str) -> io::Result<String> {
+    let (start, bytes) = read_with_retry(config.retry, || match &config.byte_range {
+        Some(range) => {
+            let (start, bytes) = read_byte_range(File::open(path)?, range)?;
+            Ok((Some(start), bytes))
+        }
+        None => Ok((None, fs::read(path)?)),
+    })?;
+    let origin = Utf8Origin {
+        start,
+        terminator: config.line_terminator.unwrap_or('\n'),
+    };
+    decode_utf8(bytes, config.strict_utf8.then_some(origin))
+}
+
+/// Runs `read`, retrying it up to `retries` more times while it fails with an
+/// error that may go away on its own, such as an interrupted read on a
+/// network filesystem.
+///
+/// Permanent errors like a missing file are returned straight away, as is
+/// the last error once the retries run out. Each retry is announced on
+/// stderr.
+fn read_with_retry<T>(retries: u32, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match read() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                eprintln!("warning: read failed ({err}), retrying ({attempt}/{retries})");
+                thread::sleep(RETRY_BACKOFF * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns true for I/O errors worth retrying.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Reads only the bytes in `range` from `source`.
+///
+/// The range is taken literally: a line crossing either boundary is searched
+/// as just the part that falls inside it, and a range running past the end of
+/// the file stops at the end; a reversed range reads nothing. A UTF-8
+/// character cut by either boundary is dropped, so offsets landing inside one
+/// do not make the text undecodable.
+///
+/// Returns the bytes kept along with the file offset of the first of them.
+fn read_byte_range(mut source: impl Read + Seek, range: &Range<u64>) -> io::Result<(u64, Vec<u8>)> {
+    source.seek(SeekFrom::Start(range.start))?;
+    let mut bytes = Vec::new();
+    source
+        .take(range.end.saturating_sub(range.start))
+        .read_to_end(&mut bytes)?;
+    let dropped = trim_cut_chars(&mut bytes);
+    Ok((range.start + dropped as u64, bytes))
+}
+
+/// Drops the pieces of multi-byte UTF-8 characters left at either end of
+/// `bytes` by cutting them out of a longer text.
+///
+/// Only the edges are touched: invalid bytes anywhere else are kept for
+/// `decode_utf8` to report. Returns how many bytes were dropped at the start.
+fn trim_cut_chars(bytes: &mut Vec<u8>) -> usize {
+    // A character is at most four bytes, so at most three continuation
+    // bytes of one can lead the range.
+    let lead = bytes
+        .iter()
+        .take(3)
+        .take_while(|&&byte| byte & 0b1100_0000 == 0b1000_0000)
+        .count();
+    bytes.drain(..lead);
+
+    // The last character starts at the last byte that is not a continuation.
+    let last = bytes
+        .iter()
+        .rev()
+        .take(4)
+        .position(|&byte| byte & 0b1100_0000 != 0b1000_0000)
+        .map(|from_end| bytes.len() - 1 - from_end);
+    if let Some(last) = last {
+        // No `error_len` means the bytes stop partway through a character.
+        if let Err(err) = std::str::from_utf8(&bytes[last..]) {
+            if err.error_len().is_none() {
+                bytes.truncate(last);
+            }
+        }
+    }
+    lead
+}
+
+/// Where the bytes given to `decode_utf8` came from, so a `--strict-utf8`
+/// error can point into the file.
+#[derive(Clone, Copy)]
+struct Utf8Origin {
+    /// File offset of the first byte, when only a `--byte-range` was read.
+    start: Option<u64>,
+    /// Character ending a line.
+    terminator: char,
+}
+
+/// Decodes the bytes read from a file as UTF-8.
+///
+/// With a `strict` origin, the error names the file offset of the first
+/// invalid sequence and its 1-based line. Lines are only counted within the
+/// bytes read, so after a byte range the error says the line is one of the
+/// range.
+fn decode_utf8(bytes: Vec<u8>, strict: Option<Utf8Origin>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|err| {
+        let Some(origin) = strict else {
+            return io::Error::new(io::ErrorKind::InvalidData, err);
+        };
+        let offset = err.utf8_error().valid_up_to();
+        let line = std::str::from_utf8(&err.as_bytes()[..offset])
+            .map_or(0, |valid| valid.matches(origin.terminator).count())
+            + 1;
+        let message = match origin.start {
+            Some(start) => format!(
+                "invalid UTF-8 at byte {} (line {line} of the byte range)",
+                start + offset as u64
+            ),
+            None => format!("invalid UTF-8 at byte {offset} (line {line})"),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// Counters collected while scanning a file, reported with `--stats`.
+#[derive(Debug, Default)]
+struct ScanStats {
+    /// Lines actually iterated over, matching or not.
+    lines: usize,
+    /// Matching lines written to the output.
+    matches: usize,
+    /// Lines not matching any query.
+    non_matching: usize,
+    /// Lines that are empty or only whitespace.
+    empty_lines: usize,
+    /// Bytes covered by the matched substrings themselves, only summed for
+    /// `--stats`.
+    matched_bytes: usize,
+    /// Bytes of the matching lines, each with one output terminator, only
+    /// summed for `--bytes-only`.
+    line_bytes: usize,
+    /// 1-based line numbers of the matches, only collected for `--histogram`.
+    match_lines: Vec<usize>,
+}
+
+/// Writes every line of `contents` matching the configured query to `out`,
+/// each ended by the configured `line_ending`.
+///
+/// The options shaping the output, in the order of the `# Options` list on
+/// `Config::build`:
+/// * `dedup_lines` - A line is only written the first time it is seen; the
+///   set of seen lines lives for a single call, so it starts empty per file
+/// * `count_non_matching` - The number of lines that did not match is
+///   written instead of any line
+/// * `bytes_only` - The total size of the matching lines, as they would be
+///   written without a prefix, is written instead of any line
+/// * `top` - Matching lines are held back and only the best ranked are
+///   written
+/// * `skip` - The first `skip` matching lines, in file order, are neither
+///   written nor ranked
+/// * `prefix` - Precedes every line written, the `empty_marker` included
+/// * `empty_marker` - Written when no matching line is left after `skip`
+/// * `replace_transform` - Rewrites each match before the line is written
+/// * `replace_diff` - Only lines changed by `replace_transform` are written,
+///   as a `- ` / `+ ` pair
+/// * `no_final_newline` - The `line_ending` ending the output is dropped
+/// * `color_theme` - Highlights the matches of lines printed as-is
+/// * `max_highlights` - Caps the highlighted matches per line
+/// * `positions` - A matching line is followed by an indented line listing
+///   its match ranges as `start-end` byte offsets, end exclusive, into the
+///   text as written; with `replace_diff` it follows the `+` line
+fn print_matches(config: &Config, contents: &str, out: &mut impl Write) -> io::Result<ScanStats> {
+    adapt_output(config, out, |mut out| {
+        write_matches(config, contents, &mut out)
+    })
+}
+
+/// Writes the `--diff-lines` difference of `contents` and `other` to `out`,
+/// honoring `no_final_newline` like `print_matches`.
+fn print_diff_lines(
+    config: &Config,
+    contents: &str,
+    other: &str,
+    out: &mut impl Write,
+) -> io::Result<ScanStats> {
+    adapt_output(config, out, |mut out| {
+        write_diff_lines(config, contents, other, &mut out)
+    })
+}
+
+/// Runs `write` on `out`, first wrapping it in `NoFinalNewline` when
+/// `no_final_newline` is set.
+fn adapt_output(
+    config: &Config,
+    out: &mut impl Write,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<ScanStats>,
+) -> io::Result<ScanStats> {
+    if config.no_final_newline {
+        let mut out = NoFinalNewline {
+            inner: out,
+            terminator: config.line_ending.as_str().as_bytes(),
+            pending: false,
+        };
+        return write(&mut out);
+    }
+    write(out)
+}
+
+/// Writer that holds back a trailing line terminator until more output
+/// follows it, so whatever was written last is left unterminated.
+struct NoFinalNewline<W> {
+    inner: W,
+    terminator: &'static [u8],
+    pending: bool,
+}
+
+impl<W: Write> Write for NoFinalNewline<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pending {
+            self.inner.write_all(self.terminator)?;
+        }
+        let (body, pending) = match buf.strip_suffix(self.terminator) {
+            Some(body) => (body, true),
+            None => (buf, false),
+        };
+        self.inner.write_all(body)?;
+        self.pending = pending;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Does the work of `print_matches` on an already adapted writer.
+fn write_matches(config: &Config, contents: &str, out: &mut impl Write) -> io::Result<ScanStats> {
+    let matcher = QueryMatcher::new(config);
+
+    let prefix = config.prefix.as_deref().unwrap_or("");
+    let eol = config.line_ending.as_str();
+
+    let mut stats = ScanStats::default();
+    let mut seen = HashSet::new();
+    let mut ranked = Vec::new();
+    for line in split_lines(contents, config.line_terminator) {
+        stats.lines += 1;
+        if line.trim().is_empty() {
+            stats.empty_lines += 1;
+        }
+        if !matcher.is_match(line) {
+            stats.non_matching += 1;
+            continue;
+        }
+        if config.dedup_lines && !seen.insert(line) {
+            continue;
+        }
+        stats.matches += 1;
+        if config.stats {
+            stats.matched_bytes += matched_bytes(config, line);
+        }
+        if config.histogram {
+            stats.match_lines.push(stats.lines);
+        }
+        if stats.matches <= config.skip {
+            continue;
+        }
+        if config.bytes_only {
+            stats.line_bytes += line.len() + eol.len();
+        }
+        if config.count_non_matching || config.bytes_only {
+            continue;
+        }
+        if config.top.is_some() {
+            ranked.push((matched_bytes(config, line), line));
+            continue;
+        }
+        // Where the matches sit in the text written, when it is not `line`.
+        let written_spans = match config.replace_transform {
+            Some(transform) if config.replace_diff => {
+                let (replaced, spans) = transform_matches(config, line, transform);
+                if replaced == line {
+                    continue;
+                }
+                write!(out, "{prefix}- {line}{eol}")?;
+                write!(out, "{prefix}+ {replaced}{eol}")?;
+                Some(spans)
+            }
+            Some(transform) => {
+                let (line, spans) = transform_matches(config, line, transform);
+                write!(out, "{prefix}{line}{eol}")?;
+                Some(spans)
+            }
+            None => {
+                match &config.color_theme {
+                    Some(theme) => {
+                        let mut spans = line_spans(config, line);
+                        spans.truncate(config.max_highlights.unwrap_or(usize::MAX));
+                        let line = paint_spans(line, &spans, &theme.matches);
+                        write!(out, "{prefix}{line}{eol}")?;
+                    }
+                    None => write!(out, "{prefix}{line}{eol}")?,
+                }
+                None
+            }
+        };
+        if config.positions {
+            let positions: Vec<String> = written_spans
+                .unwrap_or_else(|| line_spans(config, line))
+                .iter()
+                .map(|span| format!("{}-{}", span.start, span.end))
+                .collect();
+            write!(out, "{prefix}  @ {}{eol}", positions.join(", "))?;
+        }
+    }
+
+    if config.count_non_matching {
+        write!(out, "{prefix}{}{eol}", stats.non_matching)?;
+    }
+    if config.bytes_only {
+        write!(out, "{prefix}{}{eol}", stats.line_bytes)?;
+    }
+
+    if let Some(top) = config.top {
+        // The sort is stable, so equally weighted lines stay in file order.
+        ranked.sort_by_key(|&(weight, _)| Reverse(weight));
+        for (weight, line) in ranked.into_iter().take(top) {
+            write!(out, "{prefix}{weight}: {line}{eol}")?;
+        }
+    }
+
+    // Matches passed over by `skip` were never written, so a page past the
+    // last match still gets the marker.
+    if stats.matches <= config.skip {
+        if let Some(marker) = &config.empty_marker {
+            write!(out, "{prefix}{marker}{eol}")?;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// A compiled line matcher that can be reused across many inputs.
+pub trait Matcher {
+    /// Returns true when `line` matches.
+    fn is_match(&self, line: &str) -> bool;
+
+    /// Returns the lines of `contents` that match, in order.
+    fn search<'a>(&self, contents: &'a str) -> Vec<&'a str> {
+        contents
+            .lines()
+            .filter(|line| self.is_match(line))
+            .collect()
+    }
+}
+
+/// Builds a `Matcher` for the queries of `config`, honoring its case folding,
+/// `folds`, `word_unicode` and `line_terminator`.
+///
+/// The queries are split and folded once here, so searching many inputs with
+/// the matcher does not repeat that work.
+///
+/// # Case sensitivity
+/// `Config::ignore_case` is inverted: `false`, the default, gives a
+/// case-insensitive matcher and `true` a case-sensitive one. This is the
+/// opposite of the `ignore_case` argument of `highlight_line`.
+///
+/// # Errors
+/// * Returns an error if `query_delimiter` leaves no query to match; a
+///   `Config` from `Config::build` never does, so this only concerns configs
+///   built by hand
+///
+/// # Examples
+/// ```
+/// # use command_line_software::{build_matcher, Config, Matcher};
+/// let config = Config {
+///     query: "rust".to_string(),
+///     ..Default::default()
+/// };
+/// let matcher = build_matcher(&config).unwrap();
+///
+/// assert_eq!(matcher.search("Rust is fast\ngo is simple"), vec!["Rust is fast"]);
+/// assert_eq!(matcher.search("trust me\nrusty nail"), vec!["trust me", "rusty nail"]);
+///
+/// // `ignore_case: true` means the case must match
+/// let config = Config { ignore_case: true, ..config };
+/// let matcher = build_matcher(&config).unwrap();
+/// assert_eq!(matcher.search("Rust is fast\nrust is safe"), vec!["rust is safe"]);
+/// ```
+pub fn build_matcher(config: &Config) -> Result<Box<dyn Matcher>, &'static str> {
+    let matcher = QueryMatcher::new(config);
+    if matcher.queries.is_empty() {
+        return Err("no query to match");
+    }
+    Ok(Box::new(matcher))
+}
+
+/// The `Matcher` used for every search: matches lines containing any of the
+/// configured queries.
+struct QueryMatcher {
+    /// The queries, already folded unless `case_sensitive`.
+    queries: Vec<String>,
+    folds: Vec<(char, char)>,
+    case_sensitive: bool,
+    word_unicode: bool,
+    line_terminator: Option<char>,
+}
+
+impl QueryMatcher {
+    fn new(config: &Config) -> QueryMatcher {
+        // A `Config` built by hand may hold raw rules rather than a table.
+        let folds = fold_table(&config.folds);
+        let queries = config.queries().into_iter().map(|query| {
+            if config.ignore_case {
+                query.to_string()
+            } else {
+                apply_folds(Cow::Owned(query.to_lowercase()), &folds).into_owned()
+            }
+        });
+        QueryMatcher {
+            queries: queries.collect(),
+            folds,
+            case_sensitive: config.ignore_case,
+            word_unicode: config.word_unicode,
+            line_terminator: config.line_terminator,
+        }
+    }
+}
+
+impl Matcher for QueryMatcher {
+    fn is_match(&self, line: &str) -> bool {
+        let line = if self.case_sensitive {
+            Cow::Borrowed(line)
+        } else {
+            apply_folds(fold_line(line), &self.folds)
+        };
+        self.queries.iter().any(|query| {
+            if self.word_unicode {
+                contains_word(&line, query)
+            } else {
+                contains_query(&line, query)
+            }
+        })
+    }
+
+    fn search<'a>(&self, contents: &'a str) -> Vec<&'a str> {
+        split_lines(contents, self.line_terminator)
+            .filter(|line| self.is_match(line))
+            .collect()
+    }
+}
+
+/// Writes the `--diff-lines` set difference: every matching line of
+/// `contents` that is not a line of `other`, then, with `symmetric`, every
+/// matching line of `other` that is not a line of `contents`.
+///
+/// Lines are compared exactly, whatever the case folding, and each is
+/// written once, in the order it first appears. The `empty_marker` is written
+/// when no line is, and the stats only count the lines written, as matches.
+fn write_diff_lines(
+    config: &Config,
+    contents: &str,
+    other: &str,
+    out: &mut impl Write,
+) -> io::Result<ScanStats> {
+    let matcher = QueryMatcher::new(config);
+    let prefix = config.prefix.as_deref().unwrap_or("");
+    let eol = config.line_ending.as_str();
+
+    let lines: HashSet<&str> = split_lines(contents, config.line_terminator).collect();
+    let other_lines: HashSet<&str> = split_lines(other, config.line_terminator).collect();
+    let mut unique = vec![(contents, &other_lines)];
+    if config.symmetric {
+        unique.push((other, &lines));
+    }
+
+    let mut written = HashSet::new();
+    for (source, excluded) in unique {
+        for line in split_lines(source, config.line_terminator) {
+            if matcher.is_match(line) && !excluded.contains(line) && written.insert(line) {
+                write!(out, "{prefix}{line}{eol}")?;
+            }
+        }
+    }
+
+    if written.is_empty() {
+        if let Some(marker) = &config.empty_marker {
+            write!(out, "{prefix}{marker}{eol}")?;
+        }
+    }
+    Ok(ScanStats {
+        matches: written.len(),
+        ..ScanStats::default()
+    })
+}
+
+/// Finds the byte ranges of the configured queries in a matching line,
+/// honoring case folding and `--word-unicode`.
+///
+/// Where the matches of different queries overlap, the leftmost (and then
+/// longest) one wins, so the ranges never overlap.
+fn line_spans(config: &Config, line: &str) -> Vec<Range<usize>> {
+    let mut spans: Vec<Range<usize>> = config
+        .queries()
+        .into_iter()
+        .flat_map(|query| search_spans(query, line, !config.ignore_case, &config.folds))
+        .collect();
+    if config.word_unicode {
+        spans.retain(|span| is_whole_word(line, span));
+    }
+    spans.sort_by_key(|span| (span.start, Reverse(span.end)));
+
+    let mut kept: Vec<Range<usize>> = Vec::with_capacity(spans.len());
+    for span in spans {
+        if kept.last().is_none_or(|last| last.end <= span.start) {
+            kept.push(span);
+        }
+    }
+    kept
+}
+
+/// Sums the lengths of every match in `line`, in bytes.
+fn matched_bytes(config: &Config, line: &str) -> usize {
+    line_spans(config, line).iter().map(|span| span.len()).sum()
+}
+
+/// Rewrites every occurrence of the configured query in `line` with
+/// `transform`, leaving the rest of the line untouched.
+///
+/// Also returns the byte ranges of the rewritten matches in the new line,
+/// which move when a transform changes the byte length of a match, as `ı`
+/// to `I` does.
+fn transform_matches(
+    config: &Config,
+    line: &str,
+    transform: Transform,
+) -> (String, Vec<Range<usize>>) {
+    let mut transformed = String::with_capacity(line.len());
+    let mut spans = Vec::new();
+    let mut last = 0;
+    for span in line_spans(config, line) {
+        transformed.push_str(&line[last..span.start]);
+        let start = transformed.len();
+        transformed.push_str(&transform.apply(&line[span.clone()]));
+        spans.push(start..transformed.len());
+        last = span.end;
+    }
+    transformed.push_str(&line[last..]);
+    (transformed, spans)
+}
+
+/// Splits `contents` into lines.
+///
+/// Without a custom `terminator` this behaves like `str::lines`, accepting
+/// both `\n` and `\r\n`. With one, lines are split on that character only,
+/// which covers files such as classic Mac text that end lines with a bare `\r`.
+fn split_lines(contents: &str, terminator: Option<char>) -> Box<dyn Iterator<Item = &str> + '_> {
+    match terminator {
+        Some(terminator) => Box::new(contents.split_terminator(terminator)),
+        None => Box::new(contents.lines()),
+    }
+}
+
 /// Performs a case-sensitive search for a query within file contents.
 ///
 /// # Arguments
@@ -140,13 +1510,14 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 ///
 /// # Examples
 /// ```
+/// # use command_line_software::search;
 /// let contents = "Rust is a systems programming language.\nRust is safe and fast.";
 /// let results = search("Rust", contents);
 /// assert_eq!(results, vec![
 ///     "Rust is a systems programming language.",
 ///     "Rust is safe and fast."
 /// ]);
-/// 
+///
 /// // Case-sensitive search (note the difference)
 /// let case_sensitive_results = search("rust", contents);
 /// assert_eq!(case_sensitive_results, Vec::<&str>::new());
@@ -154,9 +1525,21 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
     contents
         .lines()
-        .filter(|line| line.contains(query))
+        .filter(|line| contains_query(line, query))
         .collect()
 }
+
+/// Returns true when `line` contains `query`.
+///
+/// A one-byte query (which in UTF-8 is always ASCII) is looked up as a plain
+/// byte, which the standard library scans with `memchr` instead of running
+/// the general substring search.
+fn contains_query(line: &str, query: &str) -> bool {
+    match *query.as_bytes() {
+        [byte] => line.as_bytes().contains(&byte),
+        _ => line.contains(query),
+    }
+}
 /// Performs a case-insensitive search for a query within file contents.
 ///
 /// # Arguments
@@ -168,13 +1551,14 @@ pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
 ///
 /// # Examples
 /// ```
+/// # use command_line_software::search_case_insensitive;
 /// let contents = "Rust is a systems programming language.\nrust is safe and fast.";
 /// let results = search_case_insensitive("rust", contents);
 /// assert_eq!(results, vec![
 ///     "Rust is a systems programming language.",
 ///     "rust is safe and fast."
 /// ]);
-/// 
+///
 /// // Different case variations are matched
 /// let mixed_case_results = search_case_insensitive("RuSt", contents);
 /// assert_eq!(mixed_case_results, vec![
@@ -186,18 +1570,279 @@ pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a st
     let query = query.to_lowercase();
     contents
         .lines()
-        .filter(|line| line.to_lowercase().contains(&query))
+        .filter(|line| fold_line(line).contains(&query))
         .collect()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn one_result() {
-        let query = "duct";
-        let contents = "\
+/// Highlights every occurrence of a query within a line.
+///
+/// # Arguments
+/// * `line` - The line to highlight
+/// * `query` - The search term whose occurrences are highlighted
+/// * `style` - The style wrapped around each occurrence
+/// * `ignore_case` - Whether occurrences are found regardless of case
+///
+/// # Returns
+/// The line with each occurrence wrapped in the style's escape codes. When
+/// matching ignores case, the highlighted text keeps the line's own casing.
+///
+/// # Examples
+/// ```
+/// # use command_line_software::{highlight_line, Style};
+/// let style = Style::new("1;31");
+/// assert_eq!(
+///     highlight_line("Rust and rust", "rust", &style, true),
+///     "\x1b[1;31mRust\x1b[0m and \x1b[1;31mrust\x1b[0m"
+/// );
+///
+/// // Case-sensitive highlighting only wraps the exact text
+/// assert_eq!(
+///     highlight_line("Rust and rust", "rust", &style, false),
+///     "Rust and \x1b[1;31mrust\x1b[0m"
+/// );
+/// ```
+pub fn highlight_line(line: &str, query: &str, style: &Style, ignore_case: bool) -> String {
+    paint_spans(line, &search_spans(query, line, ignore_case, &[]), style)
+}
+
+/// Wraps each of the byte ranges `spans` of `line` in `style`.
+fn paint_spans(line: &str, spans: &[Range<usize>], style: &Style) -> String {
+    let mut highlighted = String::with_capacity(line.len());
+    let mut last = 0;
+    for span in spans {
+        highlighted.push_str(&line[last..span.start]);
+        highlighted.push_str(&style.paint(&line[span.clone()]));
+        last = span.end;
+    }
+    highlighted.push_str(&line[last..]);
+    highlighted
+}
+
+/// Escapes regex metacharacters so `query` matches literally when used as a
+/// regular expression pattern.
+///
+/// # Arguments
+/// * `query` - The literal text to escape
+///
+/// # Returns
+/// The pattern source that matches exactly `query`
+///
+/// # Examples
+/// ```
+/// # use command_line_software::escape_query;
+/// assert_eq!(escape_query("a.b"), "a\\.b");
+/// assert_eq!(escape_query("(1+1)"), "\\(1\\+1\\)");
+/// ```
+pub fn escape_query(query: &str) -> String {
+    let mut escaped = String::with_capacity(query.len());
+    for c in query.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Converts a shell-style glob into an equivalent regex pattern.
+///
+/// # Arguments
+/// * `glob` - The glob to convert
+///
+/// # Returns
+/// A pattern anchored at both ends, since a glob always describes the whole
+/// text, where `*` becomes `.*`, `?` becomes `.` and a `[...]` class is kept
+/// as a regex class (`[!...]` becoming `[^...]`). Everything else, including
+/// a `[` with no closing `]`, matches literally.
+///
+/// # Examples
+/// ```
+/// # use command_line_software::glob_to_regex;
+/// assert_eq!(glob_to_regex("*.rs"), "^.*\\.rs$");
+/// assert_eq!(glob_to_regex("file?.[!ch]"), "^file.\\.[^ch]$");
+/// ```
+pub fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("^");
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => match glob_class_end(&chars, i) {
+                Some(end) => {
+                    regex.push('[');
+                    let mut j = i + 1;
+                    if matches!(chars[j], '!' | '^') {
+                        regex.push('^');
+                        j += 1;
+                    }
+                    for (k, &c) in chars.iter().enumerate().take(end).skip(j) {
+                        // A `-` between two members is a range, anything else is literal.
+                        let is_range = c == '-' && k > j && k + 1 < end;
+                        if !is_range && "\\[]^&~-".contains(c) {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                    regex.push(']');
+                    i = end;
+                }
+                None => regex.push_str("\\["),
+            },
+            c => regex.push_str(&escape_query(c.encode_utf8(&mut [0; 4]))),
+        }
+        i += 1;
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// Finds the `]` closing the glob class opened at `chars[open]`.
+///
+/// A `]` right after the opening bracket (or after its `!`/`^`) is a member
+/// of the class rather than its end, as in shells.
+fn glob_class_end(chars: &[char], open: usize) -> Option<usize> {
+    let mut first = open + 1;
+    if matches!(chars.get(first), Some('!' | '^')) {
+        first += 1;
+    }
+    (first + 1..chars.len()).find(|&i| chars[i] == ']')
+}
+
+/// Returns true when `query` occurs in `line` as a whole word.
+///
+/// An occurrence counts when the characters on either side of it are not
+/// word characters (Unicode alphanumerics and `_`) or are the edge of the
+/// line, so accented letters are treated as part of a word.
+fn contains_word(line: &str, query: &str) -> bool {
+    let mut start = 0;
+    while let Some(pos) = line[start..].find(query) {
+        let begin = start + pos;
+        if is_whole_word(line, &(begin..begin + query.len())) {
+            return true;
+        }
+        match line[begin..].chars().next() {
+            Some(c) => start = begin + c.len_utf8(),
+            None => break,
+        }
+    }
+    false
+}
+
+/// Returns true when the text at `span` is not directly preceded or followed
+/// by a word character.
+fn is_whole_word(line: &str, span: &Range<usize>) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let before = line[..span.start].chars().next_back();
+    let after = line[span.end..].chars().next();
+    !before.is_some_and(is_word) && !after.is_some_and(is_word)
+}
+
+/// Finds the byte ranges of every non-overlapping occurrence of `query` in
+/// `line`.
+///
+/// When `case_insensitive` is set, `line` is folded one character at a time,
+/// including the extra `folds` equivalences, and each match is mapped back
+/// onto the original line, so the ranges always cover the line's own text
+/// even where folding changes its byte length.
+fn search_spans(
+    query: &str,
+    line: &str,
+    case_insensitive: bool,
+    folds: &[(char, char)],
+) -> Vec<Range<usize>> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    if !case_insensitive {
+        return line
+            .match_indices(query)
+            .map(|(start, matched)| start..start + matched.len())
+            .collect();
+    }
+
+    let fold = |c: char| c.to_lowercase().map(|l| translate(l, folds));
+    let query: String = query.chars().flat_map(fold).collect();
+    let mut folded = String::with_capacity(line.len());
+    // Original byte offset of the character each folded byte came from.
+    let mut origin = Vec::with_capacity(line.len());
+    for (offset, c) in line.char_indices() {
+        folded.extend(fold(c));
+        origin.resize(folded.len(), offset);
+    }
+
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    for (folded_start, matched) in folded.match_indices(&query) {
+        let start = origin[folded_start];
+        let last = origin[folded_start + matched.len() - 1];
+        let end = last + line[last..].chars().next().map_or(0, char::len_utf8);
+        if spans.last().is_none_or(|previous| previous.end <= start) {
+            spans.push(start..end);
+        }
+    }
+    spans
+}
+
+/// Maps `c` through the user-defined `--fold` equivalences, given as a
+/// `fold_table`.
+fn translate(c: char, folds: &[(char, char)]) -> char {
+    folds
+        .iter()
+        .find(|(from, _)| *from == c)
+        .map_or(c, |(_, to)| *to)
+}
+
+/// Applies the `--fold` equivalences to already lowercased text, borrowing
+/// it unchanged when no rule applies.
+fn apply_folds<'a>(text: Cow<'a, str>, folds: &[(char, char)]) -> Cow<'a, str> {
+    if text.chars().any(|c| translate(c, folds) != c) {
+        Cow::Owned(text.chars().map(|c| translate(c, folds)).collect())
+    } else {
+        text
+    }
+}
+
+/// Lowercases a line for case-insensitive comparison.
+///
+/// Lines that are already lowercase are borrowed as-is, so only lines that
+/// actually change when folded pay for an allocation.
+fn fold_line(line: &str) -> Cow<'_, str> {
+    let is_folded = line.chars().all(|c| {
+        let mut lower = c.to_lowercase();
+        lower.next() == Some(c) && lower.next().is_none()
+    });
+
+    if is_folded {
+        Cow::Borrowed(line)
+    } else {
+        Cow::Owned(line.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a `Config` from the command-line arguments after the program name.
+    fn config(args: &[&str]) -> Config {
+        let args = ["tinnygrep"].iter().chain(args).map(|arg| arg.to_string());
+        Config::build(args).unwrap()
+    }
+
+    /// Returns what `print_matches` writes for `contents`.
+    fn output(config: &Config, contents: &str) -> String {
+        let mut out = Vec::new();
+        print_matches(config, contents, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn one_result() {
+        let query = "duct";
+        let contents = "\
 Rust:
 safe, fast, productive.
 Pick three.
@@ -220,4 +1865,812 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
+
+    #[test]
+    fn dedup_lines_prints_each_unique_match_once() {
+        let config = Config {
+            query: "rust".to_string(),
+            ignore_case: true,
+            dedup_lines: true,
+            ..Default::default()
+        };
+        let contents = "\
+rust is fast
+rust is safe
+rust is fast
+go is simple
+rust is safe";
+
+        assert_eq!("rust is fast\nrust is safe\n", output(&config, contents));
+    }
+
+    #[test]
+    fn stats_count_every_scanned_line() {
+        let config = Config {
+            query: "rust".to_string(),
+            ignore_case: true,
+            ..Default::default()
+        };
+        let contents = "\
+rust is fast
+go is simple
+rust is safe
+c is old";
+
+        let stats = print_matches(&config, contents, &mut io::sink()).unwrap();
+
+        assert_eq!(4, stats.lines);
+        assert_eq!(2, stats.matches);
+    }
+
+    #[test]
+    fn prefix_is_prepended_to_every_line() {
+        let config = config(&["--prefix", "[host1] ", "rust", "poem.txt"]);
+        let contents = "\
+rust is fast
+go is simple
+rust is safe";
+
+        assert_eq!(
+            "[host1] rust is fast\n[host1] rust is safe\n",
+            output(&config, contents)
+        );
+    }
+
+    #[test]
+    fn fold_line_borrows_lowercase_lines() {
+        assert!(matches!(fold_line("rust is fast"), Cow::Borrowed(_)));
+
+        let folded = fold_line("Rust is Fast");
+        assert!(matches!(folded, Cow::Owned(_)));
+        assert_eq!("rust is fast", folded);
+    }
+
+    #[test]
+    fn word_unicode_respects_accented_letters() {
+        // An ASCII-only boundary check would treat `é` as a separator and
+        // accept `caf` inside `café`.
+        assert!(!contains_word("un café noir", "caf"));
+        assert!(contains_word("un café noir", "café"));
+        assert!(!contains_word("décafé", "café"));
+        assert!(contains_word("naïve_x naïve", "naïve"));
+
+        let config = Config {
+            query: "café".to_string(),
+            ignore_case: true,
+            word_unicode: true,
+            ..Default::default()
+        };
+        let contents = "\
+un café noir
+cafés fermés
+le café";
+
+        assert_eq!("un café noir\nle café\n", output(&config, contents));
+    }
+
+    #[test]
+    fn line_terminator_splits_carriage_return_only_files() {
+        let config = config(&["--line-terminator", "\\r", "rust", "poem.txt"]);
+        let contents = "rust is fast\rgo is simple\rrust is safe\r";
+
+        let mut out = Vec::new();
+        let stats = print_matches(&config, contents, &mut out).unwrap();
+
+        assert_eq!(3, stats.lines);
+        assert_eq!(
+            "rust is fast\nrust is safe\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_marker_only_printed_without_matches() {
+        let mut config = config(&["--empty-marker", "<none>", "rust", "poem.txt"]);
+
+        assert_eq!("<none>\n", output(&config, "go is simple\nc is old"));
+
+        assert_eq!(
+            "rust is safe\n",
+            output(&config, "go is simple\nrust is safe")
+        );
+
+        config.prefix = Some("[host1] ".to_string());
+        assert_eq!("[host1] <none>\n", output(&config, "go is simple"));
+    }
+
+    #[test]
+    fn replace_transform_rewrites_only_matched_text() {
+        let contents = "\
+Rust and rust
+go is simple";
+        let transformed = |op: &str| {
+            let mut config = config(&["--replace-transform", op, "rust", "poem.txt"]);
+            config.ignore_case = false;
+            output(&config, contents)
+        };
+
+        assert_eq!("RUST and RUST\n", transformed("upper"));
+        assert_eq!("rust and rust\n", transformed("lower"));
+        assert_eq!("Rust and Rust\n", transformed("title"));
+    }
+
+    #[test]
+    fn title_transform_capitalizes_each_word() {
+        assert_eq!("Hello World", Transform::Title.apply("hELLO wORLD"));
+        assert_eq!("Don't-Stop", Transform::Title.apply("don't-stop"));
+    }
+
+    #[test]
+    fn case_insensitive_spans_cover_original_text() {
+        // `İ` lowercases to two characters, which shifts folded offsets.
+        let line = "İstanbul and ISTANBUL";
+        let spans = search_spans("istanbul", line, true, &[]);
+
+        let matched: Vec<&str> = spans.iter().map(|span| &line[span.clone()]).collect();
+        assert_eq!(vec!["ISTANBUL"], matched);
+        assert_eq!(vec![0..2], search_spans("i̇", line, true, &[]));
+    }
+
+    #[test]
+    fn fold_rules_add_custom_equivalences() {
+        let mut config = config(&["--fold", "0=O", "f00", "poem.txt"]);
+        config.ignore_case = false;
+        let contents = "\
+FOO bar
+f00 baz
+fuu qux";
+
+        assert_eq!("FOO bar\nf00 baz\n", output(&config, contents));
+        assert_eq!(vec![0..3], search_spans("f00", "FOO", true, &config.folds));
+        assert!(parse_fold("0O").is_err());
+    }
+
+    #[test]
+    fn fold_rules_are_equivalences() {
+        let contents = "a here\nb here\nc here\nd here";
+        let matched = |args: &[&str], query: &str| {
+            let mut args = args.to_vec();
+            args.extend([query, "poem.txt"]);
+            let mut config = config(&args);
+            config.ignore_case = false;
+            output(&config, contents)
+        };
+
+        // A chain of rules makes one group.
+        let chained = ["--fold", "a=b", "--fold", "b=c"];
+        assert_eq!("a here\nb here\nc here\n", matched(&chained, "a"));
+        assert_eq!("a here\nb here\nc here\n", matched(&chained, "c"));
+
+        // A rule given both ways still holds.
+        let two_way = ["--fold", "a=b", "--fold", "b=a"];
+        assert_eq!("a here\nb here\n", matched(&two_way, "a"));
+        assert_eq!("a here\nb here\n", matched(&two_way, "b"));
+
+        let table = fold_table(&[('a', 'b'), ('b', 'c')]);
+        assert_eq!(vec![('b', 'a'), ('c', 'a')], table);
+        assert_eq!(table, fold_table(&table));
+    }
+
+    #[test]
+    fn positions_list_every_match_on_the_line() {
+        let mut config = config(&["--positions", "rust", "poem.txt"]);
+        config.ignore_case = false;
+        let contents = "\
+the Rust and rust
+go is simple";
+
+        assert_eq!(
+            "the Rust and rust\n  @ 4-8, 13-17\n",
+            output(&config, contents)
+        );
+    }
+
+    #[test]
+    fn highlight_line_wraps_each_occurrence() {
+        let style = Style::new("32");
+
+        assert_eq!(
+            "\x1b[32mab\x1b[0m-\x1b[32mab\x1b[0m-a",
+            highlight_line("ab-ab-a", "ab", &style, false)
+        );
+        assert_eq!("no match", highlight_line("no match", "ab", &style, true));
+    }
+
+    #[test]
+    fn replace_diff_shows_changed_lines() {
+        let mut config = config(&[
+            "--replace-transform",
+            "upper",
+            "--replace-diff",
+            "rust",
+            "poem.txt",
+        ]);
+        config.ignore_case = false;
+        let contents = "\
+we like rust
+RUST is loud
+go is simple";
+
+        assert_eq!(
+            "- we like rust\n+ we like RUST\n",
+            output(&config, contents)
+        );
+
+        // Unchanged lines are not written, so they get no positions line.
+        config.positions = true;
+        assert_eq!(
+            "- we like rust\n+ we like RUST\n  @ 8-12\n",
+            output(&config, contents)
+        );
+
+        let args = ["tinnygrep", "--replace-diff", "rust", "poem.txt"];
+        assert!(Config::build(args.iter().map(|arg| arg.to_string())).is_err());
+    }
+
+    #[test]
+    fn no_final_newline_drops_only_the_last_terminator() {
+        let config = config(&["--no-final-newline", "rust", "poem.txt"]);
+        let contents = "\
+rust is fast
+go is simple
+rust is safe";
+
+        assert_eq!("rust is fast\nrust is safe", output(&config, contents));
+    }
+
+    #[test]
+    fn glob_to_regex_translates_wildcards_and_classes() {
+        assert_eq!("^.*$", glob_to_regex("*"));
+        assert_eq!("^a.c$", glob_to_regex("a?c"));
+        assert_eq!("^log\\-[0-9]\\.txt$", glob_to_regex("log-[0-9].txt"));
+        assert_eq!("^[^abc]$", glob_to_regex("[!abc]"));
+        assert_eq!("^[\\]a]$", glob_to_regex("[]a]"));
+        assert_eq!("^[a\\-]$", glob_to_regex("[a-]"));
+        assert_eq!("^\\(x\\+y\\)\\{1\\}$", glob_to_regex("(x+y){1}"));
+        assert_eq!("^\\[oops$", glob_to_regex("[oops"));
+    }
+
+    #[test]
+    fn byte_range_reads_only_the_requested_bytes() {
+        let file = io::Cursor::new("one rust\ntwo rust\nthree rust\n");
+        let (_, contents) = read_byte_range(file, &parse_byte_range("9:18").unwrap()).unwrap();
+        assert_eq!(b"two rust\n".to_vec(), contents);
+
+        // Lines cut by the range are searched as their partial text.
+        let file = io::Cursor::new("one rust\ntwo rust\n");
+        let (_, contents) = read_byte_range(file, &parse_byte_range("6:100").unwrap()).unwrap();
+        assert_eq!(b"st\ntwo rust\n".to_vec(), contents);
+
+        // Characters cut by either end are dropped rather than failing.
+        let file = io::Cursor::new("é rust é");
+        let (start, contents) = read_byte_range(file, &parse_byte_range("1:9").unwrap()).unwrap();
+        assert_eq!(b" rust ".to_vec(), contents);
+        assert_eq!(2, start);
+        let mut invalid = b"\x80\xffok\xe2\x82".to_vec();
+        assert_eq!(1, trim_cut_chars(&mut invalid));
+        assert_eq!(b"\xffok".to_vec(), invalid);
+
+        assert!(parse_byte_range("9").is_err());
+        assert!(parse_byte_range("9:3").is_err());
+        // A reversed range built by hand reads nothing rather than panicking.
+        let file = io::Cursor::new("one rust\n");
+        let reversed = Range { start: 6, end: 2 };
+        assert!(read_byte_range(file, &reversed).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn color_theme_preset_styles_matches() {
+        let config = config(&["--color-theme", "mono", "rust", "poem.txt"]);
+
+        assert_eq!(
+            "[x] \x1b[1;4mrust\x1b[0m is fast\n",
+            output(&config, "[x] rust is fast")
+        );
+
+        let args = ["tinnygrep", "--color-theme", "neon", "rust", "poem.txt"];
+        assert!(Config::build(args.iter().map(|arg| arg.to_string())).is_err());
+    }
+
+    #[test]
+    fn line_ending_crlf_terminates_output_lines() {
+        let mut config = config(&["--line-ending", "crlf", "rust", "poem.txt"]);
+        let contents = "rust is fast\ngo is simple\nrust is safe\n";
+
+        assert_eq!(
+            "rust is fast\r\nrust is safe\r\n",
+            output(&config, contents)
+        );
+
+        config.no_final_newline = true;
+        assert_eq!("rust is fast\r\nrust is safe", output(&config, contents));
+    }
+
+    #[test]
+    fn case_insensitive_highlight_wraps_original_text() {
+        let style = Style::default();
+
+        assert_eq!(
+            "say \x1b[1;31mHeLLo\x1b[0m",
+            highlight_line("say HeLLo", "hello", &style, true)
+        );
+        // Folding `İ` lengthens the line, which must not shift the highlight.
+        assert_eq!(
+            "İ \x1b[1;31mRUST\x1b[0m",
+            highlight_line("İ RUST", "Rust", &style, true)
+        );
+    }
+
+    #[test]
+    fn env_mode_searches_variables() {
+        let vars = || {
+            [
+                ("HOME", "/home/user"),
+                ("HAY", "needle in a haystack"),
+                ("LINES", "foo\r\nbar needle"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (OsString::from(key), OsString::from(value)))
+        };
+
+        // A multi-line value is still printed as one whole record.
+        let config = config(&["--env", "needle"]);
+        assert_eq!(
+            "HAY=needle in a haystack\nLINES=foo\\r\\nbar needle\n",
+            output(&config, &env_contents(vars(), None))
+        );
+
+        let contents = env_contents(vars(), Some("HAY"));
+        assert_eq!("HAY=needle in a haystack\n", contents);
+        assert!(env_contents(vars(), Some("UNSET")).is_empty());
+    }
+
+    #[test]
+    fn histogram_buckets_matches_by_line_range() {
+        let config = config(&["--histogram", "x", "poem.txt"]);
+        // 20 lines with matches on lines 1, 2, 3 and 20.
+        let mut contents = "x\nx\nx\n".to_string();
+        contents.push_str(&"-\n".repeat(16));
+        contents.push_str("x\n");
+
+        let stats = print_matches(&config, &contents, &mut io::sink()).unwrap();
+        assert_eq!(vec![1, 2, 3, 20], stats.match_lines);
+
+        let counts = histogram(&stats.match_lines, stats.lines, 4);
+        assert_eq!(vec![3, 0, 0, 1], counts);
+        let chart = render_histogram(&counts, stats.lines);
+        let bars: Vec<&str> = chart.lines().map(str::trim_start).collect();
+        assert_eq!(
+            vec![
+                "1-5 | ######################################## 3",
+                "6-10 |  0",
+                "11-15 |  0",
+                "16-20 | ############# 1",
+            ],
+            bars
+        );
+
+        assert_eq!(vec![1, 1], histogram(&[1, 2], 2, 10));
+        assert!(histogram(&[], 0, 10).is_empty());
+    }
+
+    #[test]
+    fn strict_utf8_reports_offset_and_line() {
+        let bytes = b"fine\nstill fine\nbad \xff byte\n".to_vec();
+        let whole_file = Utf8Origin {
+            start: None,
+            terminator: '\n',
+        };
+
+        let err = decode_utf8(bytes.clone(), Some(whole_file)).unwrap_err();
+        assert_eq!("invalid UTF-8 at byte 20 (line 3)", err.to_string());
+
+        assert!(decode_utf8(bytes, None).is_err());
+        assert_eq!("ok", decode_utf8(b"ok".to_vec(), Some(whole_file)).unwrap());
+
+        // After a byte range the offset is still a file offset, and lines end
+        // with the configured terminator.
+        let (start, bytes) = read_byte_range(
+            io::Cursor::new(b"0123456789\xa9a\rb\r\xff"),
+            &Range { start: 10, end: 20 },
+        )
+        .unwrap();
+        let range = Utf8Origin {
+            start: Some(start),
+            terminator: '\r',
+        };
+        let err = decode_utf8(bytes, Some(range)).unwrap_err();
+        assert_eq!(
+            "invalid UTF-8 at byte 15 (line 3 of the byte range)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn top_ranks_lines_by_matched_bytes() {
+        let config = config(&["--top", "2", "ab", "poem.txt"]);
+        let contents = "\
+ab once
+ab ab ab thrice
+no match
+ab ab twice
+ab again";
+
+        assert_eq!(
+            "6: ab ab ab thrice\n4: ab ab twice\n",
+            output(&config, contents)
+        );
+    }
+
+    #[test]
+    fn output_dir_writes_one_results_file_per_input() {
+        let root = env::temp_dir().join(format!("tinnygrep-output-dir-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let hits = root.join("hits.txt");
+        let misses = root.join("misses.txt");
+        fs::write(&hits, "rust is fast\ngo is simple\nrust is safe\n").unwrap();
+        fs::write(&misses, "go is simple\n").unwrap();
+        let out_dir = root.join("results");
+
+        for input in [&hits, &misses] {
+            let args = [
+                "--output-dir",
+                out_dir.to_str().unwrap(),
+                "rust",
+                input.to_str().unwrap(),
+            ];
+            run(config(&args)).unwrap();
+        }
+
+        assert_eq!(
+            "rust is fast\nrust is safe\n",
+            fs::read_to_string(out_dir.join("hits.txt.matches")).unwrap()
+        );
+        assert!(!out_dir.join("misses.txt.matches").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rate_is_matches_per_kilobyte() {
+        assert_eq!(2.0, match_rate(4, 2048));
+        assert_eq!(512.0, match_rate(1, 2));
+        assert_eq!(0.0, match_rate(0, 0));
+        assert_eq!(0.0, match_rate(0, 100));
+    }
+
+    #[test]
+    fn query_delimiter_behaves_like_several_queries() {
+        let mut config = config(&["--query-delimiter", ",", "ERROR, fatal,,", "log.txt"]);
+        config.ignore_case = false;
+        let contents = "\
+error: disk full
+info: all good
+Fatal: out of memory
+warning: low battery";
+
+        let mut combined = Vec::new();
+        print_matches(&config, contents, &mut combined).unwrap();
+        assert_eq!(
+            "error: disk full\nFatal: out of memory\n",
+            String::from_utf8(combined).unwrap()
+        );
+
+        config.positions = true;
+        assert_eq!(
+            "fatal error\n  @ 0-5, 6-11\n",
+            output(&config, "fatal error")
+        );
+    }
+
+    #[test]
+    fn stats_sum_the_bytes_of_matched_text() {
+        let config = config(&["--stats", "rust", "poem.txt"]);
+        let contents = "\
+rust and rust
+go is simple
+only rust here";
+
+        let stats = print_matches(&config, contents, &mut io::sink()).unwrap();
+
+        // Three occurrences of the four-byte query, not the 27 bytes of the
+        // two matching lines.
+        assert_eq!(12, stats.matched_bytes);
+    }
+
+    #[test]
+    fn retry_recovers_from_transient_read_errors() {
+        let mut attempts = 0;
+        let result = read_with_retry(2, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                _ => Ok("contents"),
+            }
+        });
+        assert_eq!("contents", result.unwrap());
+        assert_eq!(2, attempts);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = read_with_retry(2, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(io::ErrorKind::NotFound, result.unwrap_err().kind());
+        assert_eq!(1, attempts);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = read_with_retry(0, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn stats_count_empty_and_blank_lines() {
+        let config = Config {
+            query: "rust".to_string(),
+            ..Default::default()
+        };
+        let contents = "rust\n\n   \n\tgo\n\t\nrust\n";
+
+        let stats = print_matches(&config, contents, &mut io::sink()).unwrap();
+
+        assert_eq!(6, stats.lines);
+        assert_eq!(3, stats.empty_lines);
+    }
+
+    #[test]
+    fn single_byte_queries_match_like_the_general_search() {
+        let contents = "\
+a.b
+café
+x
+no dots here
+.leading";
+
+        for query in [".", "x", "é", "c", "z", ""] {
+            let general: Vec<&str> = contents
+                .lines()
+                .filter(|line| line.contains(query))
+                .collect();
+            assert_eq!(general, search(query, contents), "query {query:?}");
+        }
+    }
+
+    #[test]
+    fn pager_receives_the_results_on_stdin() {
+        let config = Config {
+            query: "rust".to_string(),
+            ..Default::default()
+        };
+
+        let mut pager = spawn_pager("cat", Stdio::piped()).unwrap();
+        let mut stdin = pager.stdin.take().unwrap();
+        print_matches(&config, "rust is fast\ngo is simple\n", &mut stdin).unwrap();
+        drop(stdin);
+        let output = pager.wait_with_output().unwrap();
+
+        assert_eq!("rust is fast\n", String::from_utf8(output.stdout).unwrap());
+        assert!(spawn_pager("tinnygrep-no-such-pager", Stdio::null()).is_err());
+        assert!(spawn_pager("  ", Stdio::null()).is_err());
+    }
+
+    #[test]
+    fn broken_pipe_discards_the_rest_of_the_output() {
+        struct Gone;
+        impl Write for Gone {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let config = Config {
+            query: "rust".to_string(),
+            ..Default::default()
+        };
+
+        let mut out = IgnoreBrokenPipe {
+            inner: Gone,
+            closed: false,
+        };
+        let stats = print_matches(&config, "rust\nrust\ngo\n", &mut out).unwrap();
+
+        assert!(out.closed);
+        assert_eq!(2, stats.matches);
+    }
+
+    #[test]
+    fn deterministic_output_ignores_the_environment() {
+        let contents = "Rust is fast\nrust is safe\ngo is simple\n";
+        let results = |args: &[&str], env_set: bool| {
+            let args = args.iter().map(|arg| arg.to_string());
+            let config = Config::build_with_env(args, |_| env_set).unwrap();
+            output(&config, contents)
+        };
+
+        let args = ["tinnygrep", "--deterministic", "rust", "poem.txt"];
+        assert_eq!(results(&args, false), results(&args, true));
+        assert_eq!(results(&args, false), results(&args, false));
+
+        // Without the flag, NO_IGNORE_CASE changes the results.
+        let args = ["tinnygrep", "rust", "poem.txt"];
+        assert_ne!(results(&args, false), results(&args, true));
+    }
+
+    #[test]
+    fn non_matching_and_matching_counts_add_up() {
+        let config = config(&["--count-non-matching", "rust", "poem.txt"]);
+        let contents = "\
+rust is fast
+go is simple
+
+c is old
+rust is safe";
+
+        let mut out = Vec::new();
+        let stats = print_matches(&config, contents, &mut out).unwrap();
+
+        assert_eq!("3\n", String::from_utf8(out).unwrap());
+        assert_eq!(stats.lines, stats.non_matching + stats.matches);
+        assert_eq!(5, stats.lines);
+    }
+
+    #[test]
+    fn skip_past_every_match_prints_the_empty_marker() {
+        let config = config(&["--skip", "5", "--empty-marker", "NONE", "line", "a.txt"]);
+        assert_eq!("NONE\n", output(&config, "line 1\nother\nline 2"));
+    }
+
+    #[test]
+    fn skip_passes_over_the_first_matches() {
+        let config = config(&["--skip", "2", "line", "poem.txt"]);
+        let contents = "line 1\nline 2\nother\nline 3\nline 4";
+
+        let mut out = Vec::new();
+        let stats = print_matches(&config, contents, &mut out).unwrap();
+
+        assert_eq!("line 3\nline 4\n", String::from_utf8(out).unwrap());
+        assert_eq!(4, stats.matches);
+    }
+
+    #[test]
+    fn diff_lines_prints_lines_missing_from_the_other_file() {
+        let root = env::temp_dir().join(format!("tinnygrep-diff-lines-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let a = root.join("a.txt");
+        let b = root.join("b.txt");
+        fs::write(&a, "rust is fast\ngo is simple\nrust is safe\nc is old\n").unwrap();
+        fs::write(&b, "go is simple\nrust is safe\nzig is new\n").unwrap();
+
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        let diff = |args: &[&str]| {
+            let config = config(args);
+            let contents = read_file(&config, &config.file_path).unwrap();
+            let other = read_file(&config, config.diff_lines.as_ref().unwrap()).unwrap();
+            let mut out = Vec::new();
+            print_diff_lines(&config, &contents, &other, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!("rust is fast\nc is old\n", diff(&["--diff-lines", a, b]));
+        assert_eq!(
+            "rust is fast\nc is old\nzig is new\n",
+            diff(&["--diff-lines", "--symmetric", a, b])
+        );
+        assert_eq!(
+            "rust is fast\n",
+            diff(&["--diff-lines", "--query", "rust", a, b])
+        );
+        assert_eq!(
+            "rust is fast\nc is old",
+            diff(&["--diff-lines", "--no-final-newline", a, b])
+        );
+        assert_eq!(
+            "NONE\n",
+            diff(&[
+                "--diff-lines",
+                "--query",
+                "zig",
+                "--empty-marker",
+                "NONE",
+                a,
+                b
+            ])
+        );
+
+        let dir = root.join("out");
+        let dir_arg = dir.to_str().unwrap();
+        run(config(&["--diff-lines", "--output-dir", dir_arg, a, b])).unwrap();
+        assert_eq!(
+            "rust is fast\nc is old\n",
+            fs::read_to_string(output_file_path(&dir, a)).unwrap()
+        );
+
+        for args in [
+            &["tinnygrep", "--diff-lines", "a.txt"][..],
+            &["tinnygrep", "--diff-lines", "--stats", "a.txt", "b.txt"],
+            &["tinnygrep", "--diff-lines", "--top", "1", "a.txt", "b.txt"],
+        ] {
+            assert!(Config::build(args.iter().map(|arg| arg.to_string())).is_err());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn max_highlights_caps_the_painted_matches() {
+        let config = config(&[
+            "--color-theme",
+            "default",
+            "--max-highlights",
+            "2",
+            "ab",
+            "poem.txt",
+        ]);
+        let style = &config.color_theme.as_ref().unwrap().matches;
+
+        let expected = format!("{} {} ab ab\n", style.paint("ab"), style.paint("ab"));
+        assert_eq!(expected, output(&config, "ab ab ab ab"));
+    }
+
+    #[test]
+    fn bytes_only_sums_matching_lines_with_their_terminators() {
+        let mut config = config(&["--bytes-only", "rust", "poem.txt"]);
+        let contents = "rust is fast\r\ngo is simple\r\nrust\r\n";
+
+        assert_eq!("18\n", output(&config, contents));
+
+        config.line_ending = LineEnding::Crlf;
+        assert_eq!("20\r\n", output(&config, contents));
+
+        // Skipped lines are not written, so they are not counted either.
+        config.skip = 1;
+        config.line_ending = LineEnding::Lf;
+        assert_eq!("5\n", output(&config, contents));
+    }
+
+    #[test]
+    fn a_query_with_only_delimiters_is_rejected() {
+        let args = ["tinnygrep", "--query-delimiter", ",", ",,", "a.txt"];
+        assert!(Config::build(args.iter().map(|arg| arg.to_string())).is_err());
+
+        let config = Config {
+            query: ", ,".to_string(),
+            query_delimiter: Some(",".to_string()),
+            ..Default::default()
+        };
+        assert!(build_matcher(&config).is_err());
+    }
+
+    #[test]
+    fn positions_follow_the_transformed_text() {
+        let config = config(&[
+            "--replace-transform",
+            "upper",
+            "--positions",
+            "kırmızı",
+            "a.txt",
+        ]);
+
+        // `ı` is two bytes but `I` is one, so every match shrinks by three.
+        assert_eq!(
+            "KIRMIZI and KIRMIZI\n  @ 0-7, 12-19\n",
+            output(&config, "kırmızı and kırmızı")
+        );
+    }
+
+    #[test]
+    fn double_dash_ends_the_options() {
+        let config = config(&["--stats", "--", "--foo", "--bar.txt"]);
+
+        assert!(config.stats);
+        assert_eq!("--foo", config.query);
+        assert_eq!("--bar.txt", config.file_path);
+        assert_eq!("--foo here\n", output(&config, "--foo here\nfoo\n"));
+    }
 }
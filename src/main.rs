@@ -2,8 +2,13 @@
 use std::env;
 use std::process;
 
+#[cfg(feature = "profile-alloc")]
+use command_line_software::CountingAlloc;
 use command_line_software::{run, Config};
 
+#[cfg(feature = "profile-alloc")]
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc::new();
 
 fn main() {
     let config: Config = Config::build(env::args()).unwrap_or_else(|err| {
@@ -11,8 +16,22 @@ fn main() {
         process::exit(1);
     });
 
+    #[cfg(feature = "profile-alloc")]
+    let before = config
+        .profile_alloc
+        .then(|| (ALLOCATOR.allocations(), ALLOCATOR.bytes()));
+
     if let Err(e) = run(config) {
         eprintln!("application error: {e}");
         process::exit(1);
     }
+
+    #[cfg(feature = "profile-alloc")]
+    if let Some((allocations, bytes)) = before {
+        eprintln!(
+            "allocations: {}, bytes allocated: {}",
+            ALLOCATOR.allocations() - allocations,
+            ALLOCATOR.bytes() - bytes
+        );
+    }
 }